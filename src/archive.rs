@@ -0,0 +1,218 @@
+// Copyright 2020  Israel Basurto
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fingerprinting a `.tar` archive (optionally gzip- or xz-compressed) the same
+//! way `visit_dir_tree` fingerprints a live directory, without extracting it.
+
+use crate::{
+    concat_hash_reader, log_progress, DirEntry, DirTree, Options, Summary, TruncatedTimestamp,
+};
+use flate2::read::GzDecoder;
+use std::io;
+use std::io::Read;
+use std::path;
+use unicode_normalization::UnicodeNormalization;
+use xz2::read::XzDecoder;
+
+/// Peek at the first bytes of `reader` to tell a gzip or xz member apart from a
+/// bare tar stream, then return a reader that transparently decompresses it.
+pub fn open_tar_decoder(mut reader: impl io::Read + 'static) -> io::Result<Box<dyn io::Read>> {
+    let mut magic = [0u8; 6];
+    let read = read_fully(&mut reader, &mut magic)?;
+    let prefix = io::Cursor::new(magic[..read].to_vec()).chain(reader);
+    if read >= 2 && magic[0] == 0x1F && magic[1] == 0x8B {
+        Ok(Box::new(GzDecoder::new(prefix)))
+    } else if read >= 6 && magic == [0xFD, b'7', b'z', b'X', b'Z', 0x00] {
+        Ok(Box::new(XzDecoder::new(prefix)))
+    } else {
+        Ok(Box::new(prefix))
+    }
+}
+
+fn read_fully(reader: &mut impl io::Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Walk the entries of a tar archive read from `reader`, populating `dir_tree`
+/// exactly as [`crate::visit_dir_tree`] would for an equivalent directory: dirs,
+/// symlinks and files land in the same `DirEntry` shape, hashed with the same
+/// `concat_hash`, honoring PAX extended headers for long paths, high-resolution
+/// mtimes and symlink targets, and respecting `options.exclude_globset`. A
+/// hardlink entry is recorded as a `File` too, reusing the hash and content
+/// type already recorded for the path it targets, the same as extracting it
+/// would leave two paths sharing one inode's content.
+/// `manifest_timestamp` should be the same instant recorded as the enclosing
+/// `ChumtreeFile::timestamp`.
+pub fn visit_tar_tree(
+    options: &Options,
+    summary: &mut Summary,
+    dir_tree: &mut DirTree,
+    reader: impl io::Read,
+    manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path_without_prefix = normalized_tar_path(&entry.path()?)?;
+        if options.exclude_globset.is_match(&path_without_prefix) {
+            continue;
+        }
+        let mtime = entry_mtime(&entry, manifest_timestamp)?;
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => {
+                dir_tree.0.insert(path_without_prefix, DirEntry::Dir);
+                summary.found_dirs += 1;
+                log_progress(summary, None);
+            }
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "symlink entry without target")
+                    })?
+                    .into_owned();
+                dir_tree
+                    .0
+                    .insert(path_without_prefix, DirEntry::Symlink { target });
+                summary.found_symlinks += 1;
+                log_progress(summary, None);
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let len = entry.header().size()?;
+                let mut total_hashed = 0_u64;
+                let (hash, content_type) =
+                    concat_hash_reader(&mut entry, options.sniff_content_type, |read| {
+                        total_hashed += read;
+                        log_progress(summary, Some((total_hashed, len)));
+                    })?;
+                let file_entry = DirEntry::File {
+                    len,
+                    mtime,
+                    hash,
+                    content_type,
+                };
+                crate::record_extension_mismatch(summary, &path_without_prefix, &file_entry);
+                dir_tree.0.insert(path_without_prefix, file_entry);
+                summary.found_files += 1;
+                summary.files_total_size += len;
+                log_progress(summary, None);
+            }
+            tar::EntryType::Link => {
+                let target = normalized_tar_path(&entry.link_name()?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "hardlink entry without target")
+                })?)?;
+                match dir_tree.0.get(&target) {
+                    Some(DirEntry::File {
+                        len,
+                        hash,
+                        content_type,
+                        ..
+                    }) => {
+                        let file_entry = DirEntry::File {
+                            len: *len,
+                            mtime,
+                            hash: hash.clone(),
+                            content_type: content_type.clone(),
+                        };
+                        crate::record_extension_mismatch(
+                            summary,
+                            &path_without_prefix,
+                            &file_entry,
+                        );
+                        dir_tree.0.insert(path_without_prefix, file_entry);
+                        summary.found_files += 1;
+                        summary.files_total_size += *len;
+                        log_progress(summary, None);
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "hardlink {:?} references unknown path {:?}",
+                                path_without_prefix, target
+                            ),
+                        ));
+                    }
+                }
+            }
+            _ => {
+                // device nodes, fifos, GNU sparse headers and the like have no
+                // content worth hashing. Directories, symlinks, regular files
+                // and hardlinks (which do have content) are all handled above.
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Normalize a tar entry path (or a hardlink/symlink target) into the same
+/// NFC-normalized form used as `DirTree` keys, so a hardlink's target can be
+/// looked up in `dir_tree`.
+fn normalized_tar_path(path: &path::Path) -> io::Result<path::PathBuf> {
+    Ok(path
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "non UTF-8 path in tar"))?
+        .nfc()
+        .collect::<String>()
+        .into())
+}
+
+/// A PAX `mtime` extension, when present, overrides the ustar header's mtime
+/// wholesale — both the integer seconds and, unlike a plain Unix inode, a
+/// fractional part that tells us unambiguously whether sub-second resolution
+/// was actually recorded. Mixing the header's seconds with the PAX value's
+/// nanos would misreport a PAX-overridden mtime that disagrees with the
+/// header field, so seconds and nanos are always read from the same source.
+fn entry_mtime<R: io::Read>(
+    entry: &tar::Entry<R>,
+    manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+) -> io::Result<TruncatedTimestamp> {
+    let pax_mtime = entry
+        .pax_extensions()?
+        .into_iter()
+        .flatten()
+        .find_map(|ext| {
+            let ext = ext.ok()?;
+            if ext.key().ok()? != "mtime" {
+                return None;
+            }
+            // Parsed as decimal text, not through f64, whose mantissa can't
+            // hold nanoseconds at present-day epoch magnitudes.
+            let value = ext.value().ok()?;
+            let (whole, fraction) = match value.find('.') {
+                Some(dot) => (&value[..dot], &value[dot + 1..]),
+                None => (value, ""),
+            };
+            if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let padded: String = fraction.chars().chain(std::iter::repeat('0')).take(9).collect();
+            Some((whole.parse::<i64>().ok()?, padded.parse::<u32>().ok()?))
+        });
+    let (seconds, nanos) = match pax_mtime {
+        Some((seconds, nanos)) => (seconds, if nanos != 0 { Some(nanos) } else { None }),
+        None => (entry.header().mtime()? as i64, None),
+    };
+    Ok(TruncatedTimestamp::new(seconds, nanos, manifest_timestamp))
+}
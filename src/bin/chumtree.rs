@@ -15,64 +15,199 @@
 
 use chumtree::ChumtreeFile;
 use std::env;
+use std::fs;
 use std::io;
 use std::path;
 
 const USAGE_TEXT: &str = "Usage:
 
-  chumtree dir-tree-path exclude-glob-pattern* > chumtree.json
+  chumtree scan [--jobs N] [--sniff-content-type] dir-tree-path exclude-glob-pattern* > chumtree.json
+  chumtree tar [--sniff-content-type] archive-path exclude-glob-pattern* > chumtree.json
+  chumtree verify chumtree.json dir-tree-path exclude-glob-pattern* > diff.json
 
-For a dir tree in 'dir-tree-path', output a JSON file with all the dirs,
+For a dir tree in 'dir-tree-path' (or the contents of the tar archive, optionally
+gzip/xz-compressed, at 'archive-path'), output a JSON file with all the dirs,
 all the symlinks and all the files with their checksum, size & mtime.
 
+'scan' hashes files concurrently on a thread pool sized to the available cores;
+pass '--jobs N' to cap it at N threads instead.
+
+'--sniff-content-type' sniffs each file's actual content type from its leading
+bytes (JPEG, PNG, gzip, PDF, ELF, Zip/OOXML) at no extra I/O cost, and records
+any file whose extension disagrees with it under 'mismatched_extensions' in
+the summary; useful for spotting misnamed or corrupt files.
+
+'verify' re-scans 'dir-tree-path' and compares it against a manifest previously
+produced by 'scan', printing a JSON diff of added/removed/modified paths and
+exiting with a nonzero status if any differences were found; useful as a
+filesystem integrity check in scripts. It inherits '--sniff-content-type' from
+the manifest being verified against, rather than taking it on the command line.
+
 Use zero or more 'exclude-glob-pattern' to exclude files or dirs that match
 the glob patterns; for example: use '.DS_Store' and '._*' to exclude macOS
 folder settings and AppleDouble resource fork files.
 See https://docs.rs/globset/0.4/globset/#syntax for the glob pattern syntax.
 ";
 
+fn usage_error() -> io::Error {
+    eprintln!("{}", USAGE_TEXT);
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "command line arguments are missing",
+    )
+}
+
+fn emit(
+    timestamp: chrono::DateTime<chrono::offset::Utc>,
+    options: chumtree::Options,
+    summary: chumtree::Summary,
+    dir_tree: chumtree::DirTree,
+) -> io::Result<()> {
+    eprintln!(
+        "\r{:>6} dirs, {:>6} symlinks, {:>6} files found, {} bytes all files total size",
+        summary.found_dirs, summary.found_symlinks, summary.found_files, summary.files_total_size
+    );
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&ChumtreeFile {
+            timestamp,
+            options,
+            summary,
+            dir_tree,
+        })
+        .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?
+    );
+    Ok(())
+}
+
 fn main() -> Result<(), io::Error> {
-    if let Some(dir) = env::args().nth(1) {
-        let dir = path::Path::new(dir.as_str());
-        let options = chumtree::Options::new(dir.clone().into(), env::args().skip(2))
-            .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())))?;
-        eprintln!(
-            "base_dir: {:?}, exclude_set: {:?}",
-            options.base_dir, options.exclude_set
-        );
-        let mut summary = chumtree::Summary::default();
-        let mut dir_tree = chumtree::DirTree::default();
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("scan") => {
+            let mut jobs = None;
+            let mut sniff_content_type = false;
+            let mut positional = Vec::new();
+            while let Some(arg) = args.next() {
+                if arg == "--jobs" {
+                    let n = args.next().ok_or_else(usage_error)?;
+                    let n = n.parse::<usize>().or_else(|e| {
+                        Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+                    })?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "--jobs must be at least 1",
+                        ));
+                    }
+                    jobs = Some(n);
+                } else if arg == "--sniff-content-type" {
+                    sniff_content_type = true;
+                } else {
+                    positional.push(arg);
+                }
+            }
+            let mut positional = positional.into_iter();
+            let dir = positional.next().ok_or_else(usage_error)?;
+            let dir = path::Path::new(dir.as_str());
+            let mut options = chumtree::Options::new(dir.clone().into(), positional)
+                .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())))?;
+            options.sniff_content_type = sniff_content_type;
+            eprintln!(
+                "base_dir: {:?}, exclude_set: {:?}",
+                options.base_dir, options.exclude_set
+            );
+            let timestamp = chrono::offset::Utc::now();
+            let mut summary = chumtree::Summary::default();
+            let mut dir_tree = chumtree::DirTree::default();
+            chumtree::visit_dir_tree(
+                &options,
+                &mut summary,
+                &mut dir_tree,
+                dir,
+                &dir.clone(),
+                timestamp,
+                jobs,
+            )?;
+            emit(timestamp, options, summary, dir_tree)
+        }
+        Some("tar") => {
+            let mut sniff_content_type = false;
+            let mut positional = Vec::new();
+            while let Some(arg) = args.next() {
+                if arg == "--sniff-content-type" {
+                    sniff_content_type = true;
+                } else {
+                    positional.push(arg);
+                }
+            }
+            let mut positional = positional.into_iter();
+            let archive_path = positional.next().ok_or_else(usage_error)?;
+            let mut options =
+                chumtree::Options::new(path::PathBuf::from(&archive_path), positional)
+                    .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())))?;
+            options.sniff_content_type = sniff_content_type;
+            eprintln!(
+                "archive: {:?}, exclude_set: {:?}",
+                options.base_dir, options.exclude_set
+            );
+            let timestamp = chrono::offset::Utc::now();
+            let mut summary = chumtree::Summary::default();
+            let mut dir_tree = chumtree::DirTree::default();
+            let file = fs::File::open(&archive_path)?;
+            let reader = chumtree::archive::open_tar_decoder(file)?;
+            chumtree::archive::visit_tar_tree(
+                &options,
+                &mut summary,
+                &mut dir_tree,
+                reader,
+                timestamp,
+            )?;
+            emit(timestamp, options, summary, dir_tree)
+        }
+        Some("verify") => {
+            let positional: Vec<String> = args.collect();
+            let mut positional = positional.into_iter();
+            let manifest_path = positional.next().ok_or_else(usage_error)?;
+            let dir = positional.next().ok_or_else(usage_error)?;
+            let dir = path::Path::new(dir.as_str());
 
-        chumtree::visit_dir_tree(&options, &mut summary, &mut dir_tree, dir, &dir.clone())?;
-        eprintln!(
-            "\r{:>6} dirs, {:>6} symlinks, {:>6} files found, {} bytes all files total size",
-            summary.found_dirs,
-            summary.found_symlinks,
-            summary.found_files,
-            summary.files_total_size
-        );
+            let manifest_text = fs::read_to_string(&manifest_path)?;
+            let previous: ChumtreeFile = serde_json::from_str(&manifest_text)
+                .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?;
 
-        dir_tree.dirs.sort_unstable();
-        dir_tree.symlinks.sort_unstable();
-        dir_tree.files.sort_unstable();
+            let mut options = chumtree::Options::new(dir.clone().into(), positional)
+                .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())))?;
+            options.sniff_content_type = previous.options.sniff_content_type;
 
-        println!(
-            "{}",
-            serde_json::to_string_pretty(&ChumtreeFile {
-                timestamp: chrono::offset::Utc::now(),
-                options,
-                summary,
-                dir_tree
-            })
-            .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?
-        );
+            let mut summary = chumtree::Summary::default();
+            let diff = chumtree::verify::verify_dir_tree(
+                &options,
+                &mut summary,
+                &previous.dir_tree,
+                dir,
+                &dir.clone(),
+                chrono::offset::Utc::now(),
+                None,
+            )?;
 
-        Ok(())
-    } else {
-        eprintln!("{}", USAGE_TEXT);
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "command line arguments are missing",
-        ))
+            eprintln!(
+                "\r{:>6} dirs, {:>6} symlinks, {:>6} files found, {} changes vs {:?}",
+                summary.found_dirs,
+                summary.found_symlinks,
+                summary.found_files,
+                diff.changes.len(),
+                manifest_path
+            );
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&diff)
+                    .or_else(|e| Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?
+            );
+            if diff.has_changes() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        _ => Err(usage_error()),
     }
 }
@@ -15,22 +15,33 @@
 
 use chrono;
 use globset::{Glob, GlobSetBuilder};
+use rayon::prelude::*;
 use seahash::SeaHasher;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::hash::Hasher;
 use std::io;
 use std::path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use unicode_normalization::UnicodeNormalization;
 
+pub mod archive;
+mod sniff;
+pub mod verify;
+
 const MEBI: usize = 1 << 20;
 
 #[derive(Debug)]
 pub struct Options {
     pub base_dir: path::PathBuf,
     pub exclude_set: HashSet<String>,
-    exclude_globset: globset::GlobSet,
+    pub(crate) exclude_globset: globset::GlobSet,
+    /// When set, every file's content is sniffed against a small magic-number
+    /// table as it's hashed (see [`DirEntry::File::content_type`]), at no extra
+    /// I/O cost. Off by default, since it's only useful for auditing a tree for
+    /// misnamed or corrupt files.
+    pub sniff_content_type: bool,
 }
 impl Options {
     pub fn new<T>(base_dir: path::PathBuf, globs_args: T) -> Result<Options, globset::Error>
@@ -51,19 +62,78 @@ impl Options {
             base_dir,
             exclude_set,
             exclude_globset,
+            sniff_content_type: false,
         })
     }
 }
+// exclude_globset can't derive Serialize/Deserialize (globset::GlobSet implements
+// neither), so Options is handled by hand: serialized from, and rebuilt through
+// Options::new() out of, the fields that are meaningful in a manifest.
+impl Serialize for Options {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Options", 3)?;
+        state.serialize_field("base_dir", &self.base_dir)?;
+        state.serialize_field("exclude_set", &self.exclude_set)?;
+        state.serialize_field("sniff_content_type", &self.sniff_content_type)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Options {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawOptions {
+            base_dir: path::PathBuf,
+            exclude_set: HashSet<String>,
+            #[serde(default)]
+            sniff_content_type: bool,
+        }
+        let raw = RawOptions::deserialize(deserializer)?;
+        let mut options =
+            Options::new(raw.base_dir, raw.exclude_set).map_err(serde::de::Error::custom)?;
+        options.sniff_content_type = raw.sniff_content_type;
+        Ok(options)
+    }
+}
 
-#[derive(Debug, Default)]
+/// The top-level structure written out as `chumtree.json`: the options and
+/// summary used to produce `dir_tree`, alongside the time the scan was taken.
+/// Deserializable so that `verify` can read a previously produced manifest back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChumtreeFile {
+    pub timestamp: chrono::DateTime<chrono::offset::Utc>,
+    pub options: Options,
+    pub summary: Summary,
+    pub dir_tree: DirTree,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Summary {
     pub found_dirs: usize,
     pub found_symlinks: usize,
     pub found_files: usize,
     pub files_total_size: u64,
+    /// Files whose sniffed content type (see [`Options::sniff_content_type`])
+    /// disagrees with their extension, e.g. a `.jpg` that's actually a PNG.
+    /// Always empty when `sniff_content_type` is off.
+    pub mismatched_extensions: Vec<ExtensionMismatch>,
 }
 
-#[derive(Debug)]
+/// One file flagged by [`Summary::mismatched_extensions`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtensionMismatch {
+    pub path: path::PathBuf,
+    pub detected_content_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct ConcatHash(Vec<u8>);
 
 #[derive(Serialize, Debug)]
@@ -71,25 +141,85 @@ pub struct SymlinkMetaData {
     target: path::PathBuf,
 }
 
-#[derive(Serialize, Debug)]
+/// An mtime truncated to the resolution its source actually reported: whole
+/// seconds always, with sub-second `nanos` only when the source actually had
+/// them (a filesystem mtime whose sub-second part is zero is recorded as whole
+/// seconds; a tar entry only gains sub-second resolution through a PAX
+/// extension).
+///
+/// `second_ambiguous` is set when `seconds` equals the second the owning
+/// manifest was itself recorded in: a file modified during that very second is
+/// indistinguishable, by mtime alone, from one that was never touched — the
+/// classic dirstate same-second hazard. [`TruncatedTimestamp::reliably_equals`]
+/// is the only comparison that is safe to trust without re-hashing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedTimestamp {
+    pub seconds: i64,
+    pub nanos: Option<u32>,
+    pub second_ambiguous: bool,
+}
+
+impl TruncatedTimestamp {
+    pub fn new(
+        seconds: i64,
+        nanos: Option<u32>,
+        manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+    ) -> TruncatedTimestamp {
+        TruncatedTimestamp {
+            seconds,
+            nanos,
+            second_ambiguous: seconds == manifest_timestamp.timestamp(),
+        }
+    }
+
+    /// A cheap mtime comparison can only be trusted when it can't be fooled by
+    /// the same-second hazard: either both sides carry nanosecond precision and
+    /// agree exactly, or neither side is ambiguous and the seconds agree.
+    /// Otherwise the caller should fall back to recomputing the content hash.
+    pub fn reliably_equals(&self, other: &TruncatedTimestamp) -> bool {
+        match (self.nanos, other.nanos) {
+            (Some(a), Some(b)) => self.seconds == other.seconds && a == b,
+            _ => !self.second_ambiguous && !other.second_ambiguous && self.seconds == other.seconds,
+        }
+    }
+
+    /// Whether the two timestamps describe the same instant, ignoring
+    /// `second_ambiguous`: that flag is relative to the manifest a timestamp
+    /// was recorded against, not a property of the file, so two timestamps for
+    /// the same file scanned into different manifests can disagree on it even
+    /// when `seconds`/`nanos` match exactly. Use this for reporting whether an
+    /// mtime actually changed; use [`Self::reliably_equals`] to decide whether
+    /// a cheap comparison can be trusted without re-hashing.
+    pub fn same_instant(&self, other: &TruncatedTimestamp) -> bool {
+        self.seconds == other.seconds && self.nanos == other.nanos
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DirEntry {
     Dir,
     Symlink {
         target: path::PathBuf,
     },
     File {
-    len: u64,
-    #[serde(serialize_with = "serialize_date_time")]
-    mtime: chrono::DateTime<chrono::offset::Utc>,
-    #[serde(serialize_with = "serialize_concat_hash")]
-    hash: ConcatHash,
+        len: u64,
+        mtime: TruncatedTimestamp,
+        #[serde(
+            serialize_with = "serialize_concat_hash",
+            deserialize_with = "deserialize_concat_hash"
+        )]
+        hash: ConcatHash,
+        /// The content type sniffed from the file's leading bytes, when
+        /// [`Options::sniff_content_type`] was on; see [`sniff::sniff`].
+        #[serde(default)]
+        content_type: Option<String>,
     },
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DirTree(BTreeMap<path::PathBuf, DirEntry>);
 
-fn log_progress(summary: &Summary, hashed_bytes: Option<(u64, u64)>) {
+pub(crate) fn log_progress(summary: &Summary, hashed_bytes: Option<(u64, u64)>) {
     eprint!(
         "\r{:>6} dirs, {:>6} symlinks, {:>6} files found",
         summary.found_dirs, summary.found_symlinks, summary.found_files
@@ -109,17 +239,33 @@ fn log_progress(summary: &Summary, hashed_bytes: Option<(u64, u64)>) {
     }
 }
 
-pub fn visit_dir_tree(
+/// A file found during the scan phase of [`visit_dir_tree`], with just enough
+/// information to hash it later without touching the filesystem again.
+pub(crate) struct PendingFile {
+    pub(crate) path_without_prefix: path::PathBuf,
+    full_path: path::PathBuf,
+    pub(crate) len: u64,
+    pub(crate) mtime: TruncatedTimestamp,
+}
+
+/// Cheap recursive scan: inserts dirs and symlinks into `dir_tree` right away
+/// (there's nothing expensive to compute for them) and appends every file found
+/// to `pending_files`, to be hashed later, possibly in parallel. `manifest_timestamp`
+/// is the time the enclosing manifest is being recorded at, used to flag mtimes
+/// that land in the same second (see [`TruncatedTimestamp`]).
+pub(crate) fn scan_dir_tree(
     options: &Options,
     summary: &mut Summary,
     dir_tree: &mut DirTree,
     dir: impl AsRef<path::Path>,
     prefix: &impl AsRef<path::Path>,
+    manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+    pending_files: &mut Vec<PendingFile>,
 ) -> io::Result<()> {
     for dir_entry in fs::read_dir(dir)? {
         let dir_entry = dir_entry?;
         let file_type = dir_entry.file_type()?;
-        let path_without_prefix = dir_entry
+        let path_without_prefix: path::PathBuf = dir_entry
             .path()
             .strip_prefix(prefix)
             .unwrap()
@@ -134,7 +280,15 @@ pub fn visit_dir_tree(
             dir_tree.0.insert(path_without_prefix, DirEntry::Dir);
             summary.found_dirs += 1;
             log_progress(summary, None);
-            visit_dir_tree(options, summary, dir_tree, dir_entry.path(), prefix)?
+            scan_dir_tree(
+                options,
+                summary,
+                dir_tree,
+                dir_entry.path(),
+                prefix,
+                manifest_timestamp,
+                pending_files,
+            )?
         } else if file_type.is_symlink() {
             let target = fs::read_link(dir_entry.path())?;
             dir_tree
@@ -144,36 +298,132 @@ pub fn visit_dir_tree(
             log_progress(summary, None);
         } else if file_type.is_file() {
             let md = dir_entry.metadata()?;
-            let mut total_hashed = 0_u64;
-            dir_tree.0.insert(
-                path_without_prefix,
-                DirEntry::File {
-                    len: md.len(),
-                    mtime: md.modified()?.into(),
-                    hash: concat_hash(dir_entry.path(), |len| {
-                        total_hashed += len;
-                        log_progress(summary, Some((total_hashed, md.len())));
-                    })?,
-                },
-            );
             summary.found_files += 1;
             summary.files_total_size += md.len();
             log_progress(summary, None);
+            let modified: chrono::DateTime<chrono::offset::Utc> = md.modified()?.into();
+            let nanos = modified.timestamp_subsec_nanos();
+            pending_files.push(PendingFile {
+                path_without_prefix,
+                full_path: dir_entry.path(),
+                len: md.len(),
+                mtime: TruncatedTimestamp::new(
+                    modified.timestamp(),
+                    if nanos != 0 { Some(nanos) } else { None },
+                    manifest_timestamp,
+                ),
+            });
         }
     }
     Ok(())
 }
 
-// custom serialization for DateTime
-fn serialize_date_time<S>(
-    dt: &chrono::DateTime<chrono::offset::Utc>,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    // display milli/nanoseconds if they are non-zero
-    serializer.serialize_str(&dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
+/// Hash `pending_files` concurrently on a rayon thread pool, capped at `jobs`
+/// threads when given (otherwise rayon's default, one per available core),
+/// reporting aggregate hashed bytes across all workers via `log_progress`.
+/// Files sniffed (see [`Options::sniff_content_type`]) whose extension
+/// disagrees with their detected type are appended to `summary`.
+pub(crate) fn hash_pending_files(
+    options: &Options,
+    pending_files: Vec<PendingFile>,
+    summary: &mut Summary,
+    jobs: Option<usize>,
+) -> io::Result<Vec<(path::PathBuf, DirEntry)>> {
+    let total_bytes: u64 = pending_files.iter().map(|pending| pending.len).sum();
+    let hashed_bytes = AtomicU64::new(0);
+    let sniff_content_type = options.sniff_content_type;
+    let progress_summary: &Summary = summary;
+    // Workers race to report hashed_bytes, but log_progress writes to stderr in
+    // place (`\r...`); without serializing those writes, concurrent eprint!
+    // calls interleave into garbage. One mutex around the print keeps the
+    // aggregate line readable without slowing the hashing itself.
+    let progress_lock = std::sync::Mutex::new(());
+    let hash_all = || -> io::Result<Vec<(path::PathBuf, DirEntry)>> {
+        pending_files
+            .into_par_iter()
+            .map(|pending| {
+                let (hash, content_type) =
+                    concat_hash(&pending.full_path, sniff_content_type, |len| {
+                        let so_far = hashed_bytes.fetch_add(len, Ordering::Relaxed) + len;
+                        let _guard = progress_lock.lock().unwrap();
+                        log_progress(progress_summary, Some((so_far, total_bytes)));
+                    })?;
+                Ok((
+                    pending.path_without_prefix,
+                    DirEntry::File {
+                        len: pending.len,
+                        mtime: pending.mtime,
+                        hash,
+                        content_type,
+                    },
+                ))
+            })
+            .collect()
+    };
+    let entries = match jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            .install(hash_all),
+        None => hash_all(),
+    }?;
+    for (path_without_prefix, entry) in entries.iter() {
+        record_extension_mismatch(summary, path_without_prefix, entry);
+    }
+    Ok(entries)
+}
+
+/// If `entry` is a file with a sniffed content type that disagrees with
+/// `path`'s extension, append it to `summary.mismatched_extensions`.
+pub(crate) fn record_extension_mismatch(
+    summary: &mut Summary,
+    path: &path::Path,
+    entry: &DirEntry,
+) {
+    if let DirEntry::File {
+        content_type: Some(content_type),
+        ..
+    } = entry
+    {
+        if sniff::is_extension_mismatch(path, content_type) {
+            summary.mismatched_extensions.push(ExtensionMismatch {
+                path: path.to_path_buf(),
+                detected_content_type: content_type.clone(),
+            });
+        }
+    }
+}
+
+/// Walk `dir` and populate `dir_tree` with every dir, symlink and file found
+/// under it, hashing file contents with `concat_hash`. Dirs and symlinks are
+/// inserted during a single serial scan; files are then hashed concurrently,
+/// see [`hash_pending_files`]. `manifest_timestamp` should be the same instant
+/// recorded as the enclosing [`ChumtreeFile::timestamp`].
+pub fn visit_dir_tree(
+    options: &Options,
+    summary: &mut Summary,
+    dir_tree: &mut DirTree,
+    dir: impl AsRef<path::Path>,
+    prefix: &impl AsRef<path::Path>,
+    manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+    jobs: Option<usize>,
+) -> io::Result<()> {
+    let mut pending_files = Vec::new();
+    scan_dir_tree(
+        options,
+        summary,
+        dir_tree,
+        dir,
+        prefix,
+        manifest_timestamp,
+        &mut pending_files,
+    )?;
+    for (path_without_prefix, entry) in hash_pending_files(options, pending_files, summary, jobs)? {
+        dir_tree.0.insert(path_without_prefix, entry);
+    }
+    log_progress(summary, None);
+    Ok(())
 }
 
 fn bufcopy<F: FnMut(u64)>(
@@ -194,12 +444,33 @@ fn bufcopy<F: FnMut(u64)>(
     }
 }
 
+/// Sniffing can't trust a single `write`: a plain file read fills the whole
+/// buffer, but a tar entry read off a `GzDecoder`/`XzDecoder` can hand back a
+/// chunk shorter than the longest magic, so a short first `write` would
+/// otherwise be mistaken for a content type miss. `Accumulating` buffers
+/// leading bytes across writes until there are enough to sniff reliably (or
+/// the file ends first).
+enum SniffState {
+    Disabled,
+    Accumulating(Vec<u8>),
+    Done,
+}
+
 struct ConcatHasherToWriteAdapter<H: Hasher> {
     hasher: H,
     concat_hash: ConcatHash,
+    sniff_state: SniffState,
+    content_type: Option<String>,
 }
 impl<H: Hasher> io::Write for ConcatHasherToWriteAdapter<H> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let SniffState::Accumulating(leading_bytes) = &mut self.sniff_state {
+            leading_bytes.extend_from_slice(buf);
+            if leading_bytes.len() >= sniff::MAX_SIGNATURE_LEN {
+                self.content_type = sniff::sniff(leading_bytes).map(str::to_string);
+                self.sniff_state = SniffState::Done;
+            }
+        }
         Hasher::write(&mut self.hasher, buf);
         self.concat_hash
             .0
@@ -212,16 +483,45 @@ impl<H: Hasher> io::Write for ConcatHasherToWriteAdapter<H> {
     }
 }
 
-fn concat_hash<F: FnMut(u64)>(path: impl AsRef<path::Path>, log: F) -> io::Result<ConcatHash> {
+fn concat_hash<F: FnMut(u64)>(
+    path: impl AsRef<path::Path>,
+    sniff: bool,
+    log: F,
+) -> io::Result<(ConcatHash, Option<String>)> {
     let mut file = fs::File::open(path)?;
+    concat_hash_reader(&mut file, sniff, log)
+}
+
+/// Same rolling concatenated-hash computation as [`concat_hash`], but reading from
+/// an arbitrary reader rather than opening a path; used by [`archive`] to hash tar
+/// entries directly off the archive stream without extracting them to disk first.
+/// When `sniff` is on, the content type of the first bytes read is returned
+/// alongside the hash, at no extra I/O cost.
+pub(crate) fn concat_hash_reader<R: io::Read, F: FnMut(u64)>(
+    reader: &mut R,
+    sniff: bool,
+    log: F,
+) -> io::Result<(ConcatHash, Option<String>)> {
     let hasher = SeaHasher::new();
     let mut buf = [0; 1 * MEBI];
     let mut concat_adapter = ConcatHasherToWriteAdapter {
         hasher,
         concat_hash: ConcatHash(Vec::new()),
+        sniff_state: if sniff {
+            SniffState::Accumulating(Vec::with_capacity(sniff::MAX_SIGNATURE_LEN))
+        } else {
+            SniffState::Disabled
+        },
+        content_type: None,
     };
-    bufcopy(&mut buf, &mut file, &mut concat_adapter, log)?;
-    Ok(concat_adapter.concat_hash)
+    bufcopy(&mut buf, reader, &mut concat_adapter, log)?;
+    // The file ended before enough bytes accumulated to hit the threshold in
+    // `write`; sniff whatever leading bytes there are, same as `sniff` does for
+    // a buffer shorter than every signature's magic.
+    if let SniffState::Accumulating(leading_bytes) = &concat_adapter.sniff_state {
+        concat_adapter.content_type = sniff::sniff(leading_bytes).map(str::to_string);
+    }
+    Ok((concat_adapter.concat_hash, concat_adapter.content_type))
 }
 
 fn serialize_concat_hash<S>(concat_hash: &ConcatHash, serializer: S) -> Result<S::Ok, S::Error>
@@ -232,7 +532,22 @@ where
         &concat_hash
             .0
             .iter()
-            .map(|x| format!("{:X?}", x))
+            .map(|x| format!("{:02X}", x))
             .collect::<String>(),
     )
 }
+
+fn deserialize_concat_hash<'de, D>(deserializer: D) -> Result<ConcatHash, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    if text.len() % 2 != 0 {
+        return Err(serde::de::Error::custom("hash string has odd length"));
+    }
+    let bytes = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<u8>, D::Error>>()?;
+    Ok(ConcatHash(bytes))
+}
@@ -0,0 +1,111 @@
+// Copyright 2020  Israel Basurto
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A small built-in table of magic-number signatures, used to tell what a file
+//! actually is from its leading bytes rather than trusting its extension.
+
+use std::path;
+
+struct Signature {
+    content_type: &'static str,
+    magic: &'static [u8],
+    /// Extensions (lowercase, without the dot) this content type is normally
+    /// found under; empty when there's no single conventional extension to
+    /// check against (e.g. ELF binaries).
+    extensions: &'static [&'static str],
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        content_type: "JPEG",
+        magic: &[0xFF, 0xD8, 0xFF],
+        extensions: &["jpg", "jpeg"],
+    },
+    Signature {
+        content_type: "PNG",
+        magic: &[0x89, 0x50, 0x4E, 0x47],
+        extensions: &["png"],
+    },
+    Signature {
+        content_type: "gzip",
+        magic: &[0x1F, 0x8B],
+        extensions: &["gz", "tgz"],
+    },
+    Signature {
+        content_type: "PDF",
+        magic: b"%PDF",
+        extensions: &["pdf"],
+    },
+    Signature {
+        content_type: "ELF",
+        magic: &[0x7F, b'E', b'L', b'F'],
+        extensions: &[],
+    },
+    Signature {
+        content_type: "Zip/OOXML",
+        magic: &[0x50, 0x4B],
+        extensions: &["zip", "jar", "docx", "xlsx", "pptx", "apk"],
+    },
+];
+
+/// The longest magic in [`SIGNATURES`]: callers that assemble a prefix to
+/// sniff across multiple reads (a tar entry can hand back a short first chunk
+/// under a `GzDecoder`/`XzDecoder`) need at least this many leading bytes
+/// buffered before a miss can be trusted as a real miss rather than a
+/// truncated magic.
+pub(crate) const MAX_SIGNATURE_LEN: usize = max_signature_len();
+
+const fn max_signature_len() -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < SIGNATURES.len() {
+        if SIGNATURES[i].magic.len() > max {
+            max = SIGNATURES[i].magic.len();
+        }
+        i += 1;
+    }
+    max
+}
+
+/// Match `buf` (the leading bytes of a file) against the signature table,
+/// longest-prefix match winning. `buf` may be shorter than a signature's magic
+/// (e.g. an empty or truncated file); such signatures simply can't match.
+pub(crate) fn sniff(buf: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .filter(|signature| buf.starts_with(signature.magic))
+        .max_by_key(|signature| signature.magic.len())
+        .map(|signature| signature.content_type)
+}
+
+/// `true` when `content_type` has a conventional extension and `path`'s
+/// extension isn't among them (a `.jpg` that sniffed as PNG, say). A path with
+/// no extension, or a content type with no conventional one to check, is never
+/// flagged as a mismatch.
+pub(crate) fn is_extension_mismatch(path: &path::Path, content_type: &str) -> bool {
+    let extension = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return false,
+    };
+    match SIGNATURES
+        .iter()
+        .find(|signature| signature.content_type == content_type)
+    {
+        Some(signature) if !signature.extensions.is_empty() => {
+            !signature.extensions.contains(&extension.as_str())
+        }
+        _ => false,
+    }
+}
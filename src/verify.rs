@@ -0,0 +1,193 @@
+// Copyright 2020  Israel Basurto
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Comparing a freshly scanned [`crate::DirTree`] against one read back from a
+//! previously produced `chumtree.json`, for use as a filesystem integrity check.
+
+use crate::{DirEntry, DirTree, Options, Summary};
+use serde::Serialize;
+use std::io;
+use std::path;
+
+/// How a single path differs between the previous manifest and the current scan.
+#[derive(Serialize, Debug)]
+#[serde(tag = "change")]
+pub enum PathChange {
+    Added,
+    Removed,
+    Modified { reasons: Vec<String> },
+}
+
+/// The full set of differences between a previous manifest's `DirTree` and a
+/// current one, keyed by path, in the same order a `DirTree` would print them.
+#[derive(Serialize, Debug, Default)]
+pub struct TreeDiff {
+    pub changes: std::collections::BTreeMap<path::PathBuf, PathChange>,
+}
+
+impl TreeDiff {
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty()
+    }
+}
+
+/// Walk `dir` like [`crate::visit_dir_tree`], but against `previous`: a file
+/// whose size and mtime reliably match its entry in `previous` (see
+/// [`crate::TruncatedTimestamp::reliably_equals`]) is taken on faith and never
+/// re-hashed; every other file is hashed as usual. This keeps `verify` cheap on
+/// an unchanged tree while still catching a same-second edit that a naive mtime
+/// comparison would miss.
+pub fn verify_dir_tree(
+    options: &Options,
+    summary: &mut Summary,
+    previous: &DirTree,
+    dir: impl AsRef<path::Path>,
+    prefix: &impl AsRef<path::Path>,
+    manifest_timestamp: chrono::DateTime<chrono::offset::Utc>,
+    jobs: Option<usize>,
+) -> io::Result<TreeDiff> {
+    let mut dir_tree = DirTree::default();
+    let mut pending_files = Vec::new();
+    crate::scan_dir_tree(
+        options,
+        summary,
+        &mut dir_tree,
+        dir,
+        prefix,
+        manifest_timestamp,
+        &mut pending_files,
+    )?;
+
+    let mut to_hash = Vec::new();
+    for pending in pending_files {
+        match previous.0.get(&pending.path_without_prefix) {
+            Some(DirEntry::File {
+                len,
+                mtime,
+                hash: previous_hash,
+                content_type,
+            }) if *len == pending.len && mtime.reliably_equals(&pending.mtime) => {
+                let file_entry = DirEntry::File {
+                    len: *len,
+                    mtime: *mtime,
+                    hash: previous_hash.clone(),
+                    content_type: content_type.clone(),
+                };
+                crate::record_extension_mismatch(
+                    summary,
+                    &pending.path_without_prefix,
+                    &file_entry,
+                );
+                dir_tree.0.insert(pending.path_without_prefix, file_entry);
+            }
+            _ => to_hash.push(pending),
+        }
+    }
+    for (path_without_prefix, entry) in crate::hash_pending_files(options, to_hash, summary, jobs)?
+    {
+        dir_tree.0.insert(path_without_prefix, entry);
+    }
+
+    Ok(diff_dir_trees(previous, &dir_tree))
+}
+
+/// Classify every path in `previous` and `current` as unchanged, added, removed
+/// or modified. Unchanged paths are left out of the result entirely.
+pub fn diff_dir_trees(previous: &DirTree, current: &DirTree) -> TreeDiff {
+    let mut changes = std::collections::BTreeMap::new();
+    for (path, previous_entry) in previous.0.iter() {
+        match current.0.get(path) {
+            None => {
+                changes.insert(path.clone(), PathChange::Removed);
+            }
+            Some(current_entry) => {
+                if let Some(reasons) = compare_entries(previous_entry, current_entry) {
+                    changes.insert(path.clone(), PathChange::Modified { reasons });
+                }
+            }
+        }
+    }
+    for path in current.0.keys() {
+        if !previous.0.contains_key(path) {
+            changes.insert(path.clone(), PathChange::Added);
+        }
+    }
+    TreeDiff { changes }
+}
+
+/// Returns `None` when the two entries are equivalent, or the list of reasons
+/// they differ otherwise.
+fn compare_entries(previous: &DirEntry, current: &DirEntry) -> Option<Vec<String>> {
+    match (previous, current) {
+        (DirEntry::Dir, DirEntry::Dir) => None,
+        (
+            DirEntry::Symlink {
+                target: previous_target,
+            },
+            DirEntry::Symlink {
+                target: current_target,
+            },
+        ) => {
+            if previous_target == current_target {
+                None
+            } else {
+                Some(vec![format!(
+                    "symlink target changed: {:?} -> {:?}",
+                    previous_target, current_target
+                )])
+            }
+        }
+        (
+            DirEntry::File {
+                len: previous_len,
+                mtime: previous_mtime,
+                hash: previous_hash,
+                content_type: previous_content_type,
+            },
+            DirEntry::File {
+                len: current_len,
+                mtime: current_mtime,
+                hash: current_hash,
+                content_type: current_content_type,
+            },
+        ) => {
+            let mut reasons = Vec::new();
+            if previous_len != current_len {
+                reasons.push(format!("size changed: {} -> {}", previous_len, current_len));
+            }
+            if !previous_mtime.same_instant(current_mtime) {
+                reasons.push(format!(
+                    "mtime changed: {:?} -> {:?}",
+                    previous_mtime, current_mtime
+                ));
+            }
+            if previous_hash != current_hash {
+                reasons.push("content hash changed".to_string());
+            }
+            if previous_content_type != current_content_type {
+                reasons.push(format!(
+                    "content type changed: {:?} -> {:?}",
+                    previous_content_type, current_content_type
+                ));
+            }
+            if reasons.is_empty() {
+                None
+            } else {
+                Some(reasons)
+            }
+        }
+        _ => Some(vec!["entry type changed".to_string()]),
+    }
+}